@@ -1,10 +1,12 @@
 use std::io;
 
-use {Command, BoxError};
+use libc::c_char;
+
+use {Command, BoxError, Namespace};
 
 
 impl Command {
-    /// Set a callback to run when child is already forked but not yet run
+    /// Add a callback to run when child is already forked but not yet run
     ///
     /// When starting a child we sometimes need more setup from the parent,
     /// for example: to configure pid namespaces for the unprivileged
@@ -16,46 +18,114 @@ impl Command {
     ///
     /// If callback returns error, process is shut down.
     ///
-    /// Each invocation **replaces** callback,
-    /// so there is only one of them can be called.
+    /// Multiple callbacks can be registered and they will be called in order
+    /// of their registration. If one returns an error the remaining callbacks
+    /// are skipped and the error is propagated.
     ///
     pub fn before_unfreeze(&mut self,
         f: impl FnMut(u32) -> Result<(), BoxError> + 'static)
     {
-        self.before_unfreeze = Some(Box::new(f));
+        self.before_unfreeze.push(Box::new(f));
     }
 
-    /// Set a callback to run just before chrooting, after chroot, the process runs in the chroot
-    /// jail not allowing it any access to other parts of the filesystem. This callback allows 
+    /// Add a callback to run just before chrooting, after chroot, the process runs in the chroot
+    /// jail not allowing it any access to other parts of the filesystem. This callback allows
     /// the client to configure anything before this happens.
-    /// This callback runs in the child process. As with the other callbacks running in the
-    /// child, do not perform any allocations or de-allocations here.
+    /// This callback runs in the child process. Like [`pre_exec`](Self::pre_exec)
+    /// it executes in the forked child, so only async-signal-safe operations are
+    /// permitted: do not perform any allocations or de-allocations, acquire
+    /// mutexes or touch the process environment here.
+    ///
+    /// Multiple callbacks can be registered and they will be called in order
+    /// of their registration.
     pub fn before_chroot(&mut self,
         f: impl Fn() -> io::Result<()> + Send + Sync + 'static)
     {
-        self.before_chroot = Some(Box::new(f));
+        self.before_chroot.push(Box::new(f));
     }
 
-    /// Set a callback to run in the child before calling exec
+    /// Schedule a closure to be run in the child just before calling `execve`
     ///
-    /// The callback is executed right before `execve` system calls.
+    /// The callback is executed right before the `execve` system call.
     /// All other modifications of the environment are already applied
     /// at this moment. It always run after ``before_unfreeze`` in parent.
     ///
-    /// **Warning** this callback must not do any memory (de)allocations,
-    /// use mutexes, otherwise process may crash or deadlock. Only bare
-    /// syscalls are allowed (use `libc` crate).
+    /// Multiple callbacks can be registered and they will be called in order
+    /// of their registration. If any callback returns an error the chain is
+    /// stopped immediately and that error is propagated to the parent.
     ///
     /// The closure is allowed to return an I/O error whose
     /// OS error code will be communicated back to the parent
     /// and returned as an error from when the spawn was requested.
     ///
-    /// Note: unlike same method in stdlib,
-    /// each invocation of this method **replaces** callback,
-    /// so there is only one of them can be called.
+    /// # Safety
+    ///
+    /// The closure runs in the child process **after** `fork` and before
+    /// `execve`. At that point only async-signal-safe operations are allowed:
+    /// the child shares the address space of the parent but runs with a single
+    /// thread, so any lock that was held by another thread at the moment of
+    /// `fork` can never be released. In practice this means `malloc`, mutex
+    /// acquisition and environment access (`std::env`) are **not** guaranteed
+    /// to work and may deadlock or observe torn state. Only bare syscalls are
+    /// safe to use here (see the `libc` crate). The caller is responsible for
+    /// upholding this contract, hence the method is `unsafe`.
+    pub unsafe fn pre_exec(&mut self,
+        f: impl FnMut() -> io::Result<()> + Send + Sync + 'static)
+    {
+        self.before_exec.push(Box::new(f));
+    }
+
+    /// Add a callback to run in the child before calling exec
+    ///
+    /// This is a safe wrapper that forwards to [`pre_exec`](Self::pre_exec).
+    /// It is deprecated because the same async-signal-safety requirements that
+    /// make `pre_exec` `unsafe` apply here too — see that method for the full
+    /// contract.
+    #[deprecated(note = "use the `pre_exec` method instead")]
     pub fn before_exec(&mut self,
-        f: impl Fn() -> io::Result<()> + Send + Sync + 'static)
+        f: impl FnMut() -> io::Result<()> + Send + Sync + 'static)
     {
-        self.before_exec = Some(Box::new(f));
+        unsafe { self.pre_exec(f) }
+    }
+
+    /// The environment that will be passed to `execve`, as a null-terminated
+    /// `environ`-style array
+    ///
+    /// The array is resolved and allocated in the **parent** process before
+    /// `fork`, so a [`pre_exec`](Self::pre_exec) or
+    /// [`before_chroot`](Self::before_chroot) callback may read it from the
+    /// forked child without allocating or acquiring the `std::env` lock. This
+    /// is the only async-signal-safe way for a child callback to inspect the
+    /// exact environment that `execve` will receive: reaching for `std::env`
+    /// in the child can deadlock or observe torn state.
+    ///
+    /// Each entry points at a `key=value` C string and the array is terminated
+    /// by a null pointer. The returned pointer is only valid for as long as the
+    /// `Command` is alive and must not be freed by the caller.
+    pub fn environ(&self) -> *const *const c_char {
+        self.environ.as_ptr()
+    }
+
+    /// Perform all configured setup and then replace the current process image
+    ///
+    /// Following the semantics of `std::os::unix::process::CommandExt::exec`,
+    /// this runs the namespace flags, uid/gid maps, chroot and the
+    /// ``before_chroot``/``before_exec`` hooks **in the current process**
+    /// wherever possible and then `execve`s directly. On success the process
+    /// image is replaced and this function never returns, so it only yields an
+    /// [`io::Error`] describing the failure.
+    ///
+    /// Setting up a pid namespace fundamentally requires a `fork` (the first
+    /// process in a new pid namespace becomes its init), which cannot be done
+    /// in place without leaving a parent behind. In that case this returns an
+    /// error of kind [`io::ErrorKind::Unsupported`] rather than silently
+    /// forking. For every other configuration the setup is applied in place.
+    pub fn exec(&mut self) -> io::Error {
+        if self.config.namespaces.contains(Namespace::Pid) {
+            return io::Error::new(io::ErrorKind::Unsupported,
+                "`exec` cannot set up a pid namespace without forking; \
+                 use `spawn` instead");
+        }
+        self.exec_in_place()
     }
 }